@@ -1,28 +1,68 @@
-use ipnet::Ipv4Net;
-use std::net::Ipv4Addr;
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+#[derive(Debug,PartialEq,Hash,Eq, Clone, Copy)]
+enum Proto {
+    Icmp,
+    Tcp,
+    Udp,
+    Any,
+}
+
+#[derive(Debug,PartialEq,Hash,Eq, Clone)]
+enum IpFlow {
+    V4 {
+        src_net: u32,
+        src_mask: u32,
+        dst_net: u32,
+        dst_mask: u32,
+    },
+    V6 {
+        src_net: u128,
+        src_mask: u128,
+        dst_net: u128,
+        dst_mask: u128,
+    },
+}
 
 #[derive(Debug,PartialEq,Hash,Eq, Clone)]
 struct Flow {
-    src_net: u32,
-    src_mask: u32,
-    dst_net: u32,
-    dst_mask: u32,
+    ip: IpFlow,
+    proto: Proto,
     src_port: u16,
     dst_port: u16,
     action: Action,
 }
 
 impl Flow{
-    fn new(src_net: Ipv4Net, src_port: u16, dst_net: Ipv4Net, dst_port: u16, action: Action) -> Self {
-        Self {  
-            src_net: as_u32_be(&src_net.addr().octets()),
-            src_mask: as_u32_be(&src_net.netmask().octets()),
+    fn new_v4(src_net: Ipv4Net, src_port: u16, dst_net: Ipv4Net, dst_port: u16, proto: Proto, action: Action) -> Self {
+        Self {
+            ip: IpFlow::V4 {
+                src_net: as_u32_be(&src_net.addr().octets()),
+                src_mask: as_u32_be(&src_net.netmask().octets()),
+                dst_net: as_u32_be(&dst_net.addr().octets()),
+                dst_mask: as_u32_be(&dst_net.netmask().octets()),
+            },
+            proto,
+            src_port,
+            dst_port,
+            action,
+        }
+    }
+
+    fn new_v6(src_net: Ipv6Net, src_port: u16, dst_net: Ipv6Net, dst_port: u16, proto: Proto, action: Action) -> Self {
+        Self {
+            ip: IpFlow::V6 {
+                src_net: as_u128_be(&src_net.addr().octets()),
+                src_mask: as_u128_be(&src_net.netmask().octets()),
+                dst_net: as_u128_be(&dst_net.addr().octets()),
+                dst_mask: as_u128_be(&dst_net.netmask().octets()),
+            },
+            proto,
             src_port,
-            dst_net: as_u32_be(&dst_net.addr().octets()),
-            dst_mask: as_u32_be(&dst_net.netmask().octets()),
             dst_port,
             action,
         }
@@ -37,55 +77,121 @@ enum Action {
 
 #[derive(Debug,Clone)]
 struct FlowTable{
-    src_map: Rc<BTreeMap<u32, HashMap<(u32,u16), bool>>>,
-    dst_map: Rc<BTreeMap<u32, HashMap<(u32,u16), bool>>>,
-    flow_map: Rc<HashMap<(u32, u32, u16, u32, u32, u16), Action>>,
+    src_map_v4: Rc<BTreeMap<u32, HashMap<(u32,u16), bool>>>,
+    dst_map_v4: Rc<BTreeMap<u32, HashMap<(u32,u16), bool>>>,
+    flow_map_v4: Rc<HashMap<(Proto, u32, u32, u16, u32, u32, u16), Action>>,
+    // Cache of every registered (src_mask, dst_mask) pair, pre-sorted most-specific
+    // first. Rebuilt only in `add_flow` when a brand-new mask is registered on either
+    // side, never per packet, since `match_flow` runs on the hot path.
+    mask_pairs_v4: Rc<Vec<(u32, u32)>>,
+    src_map_v6: Rc<BTreeMap<u128, HashMap<(u128,u16), bool>>>,
+    dst_map_v6: Rc<BTreeMap<u128, HashMap<(u128,u16), bool>>>,
+    flow_map_v6: Rc<HashMap<(Proto, u128, u128, u16, u128, u128, u16), Action>>,
+    mask_pairs_v6: Rc<Vec<(u128, u128)>>,
 }
 
 impl FlowTable {
     fn new() -> Self {
-        Self {  
-            src_map: Rc::new(BTreeMap::new()),
-            dst_map: Rc::new(BTreeMap::new()),
-            flow_map: Rc::new(HashMap::new()),
+        Self {
+            src_map_v4: Rc::new(BTreeMap::new()),
+            dst_map_v4: Rc::new(BTreeMap::new()),
+            flow_map_v4: Rc::new(HashMap::new()),
+            mask_pairs_v4: Rc::new(Vec::new()),
+            src_map_v6: Rc::new(BTreeMap::new()),
+            dst_map_v6: Rc::new(BTreeMap::new()),
+            flow_map_v6: Rc::new(HashMap::new()),
+            mask_pairs_v6: Rc::new(Vec::new()),
         }
     }
+
     fn add_flow(&mut self, flow: Flow){
-        let src_mask = 4294967295 - flow.src_mask;
-        let src_map = Rc::get_mut(&mut self.src_map).unwrap(); 
-        let res = src_map.get_mut(&src_mask);
-        match res {
-            Some(map) => {
-                map.insert((flow.src_net, flow.src_port), true);
-            },
-            None => {
-                let mut map = HashMap::new();
-                map.insert((flow.src_net, flow.src_port), true);
-                src_map.insert(src_mask, map);
-            },
-        }
+        match flow.ip {
+            IpFlow::V4 { src_net, src_mask, dst_net, dst_mask } => {
+                let mut new_mask_registered = false;
+
+                let src_mask_bin = 4294967295 - src_mask;
+                let src_map = Rc::get_mut(&mut self.src_map_v4).unwrap();
+                match src_map.get_mut(&src_mask_bin) {
+                    Some(map) => { map.insert((src_net, flow.src_port), true); },
+                    None => {
+                        let mut map = HashMap::new();
+                        map.insert((src_net, flow.src_port), true);
+                        src_map.insert(src_mask_bin, map);
+                        new_mask_registered = true;
+                    },
+                }
+
+                let dst_mask_bin = 4294967295 - dst_mask;
+                let dst_map = Rc::get_mut(&mut self.dst_map_v4).unwrap();
+                match dst_map.get_mut(&dst_mask_bin) {
+                    Some(map) => { map.insert((dst_net, flow.dst_port), true); },
+                    None => {
+                        let mut map = HashMap::new();
+                        map.insert((dst_net, flow.dst_port), true);
+                        dst_map.insert(dst_mask_bin, map);
+                        new_mask_registered = true;
+                    },
+                }
+
+                let flow_map = Rc::get_mut(&mut self.flow_map_v4).unwrap();
+                flow_map.insert((flow.proto, src_net, src_mask, flow.src_port, dst_net, dst_mask, flow.dst_port), flow.action);
 
-        let dst_mask = 4294967295 - flow.dst_mask;
-        let dst_map = Rc::get_mut(&mut self.dst_map).unwrap(); 
-        let res = dst_map.get_mut(&dst_mask);
-        match res {
-            Some(map) => {
-                map.insert((flow.dst_net, flow.dst_port), true);
+                if new_mask_registered {
+                    self.rebuild_mask_pairs_v4();
+                }
             },
-            None => {
-                let mut map = HashMap::new();
-                map.insert((flow.dst_net, flow.dst_port), true);
-                dst_map.insert(dst_mask, map);
+            IpFlow::V6 { src_net, src_mask, dst_net, dst_mask } => {
+                let mut new_mask_registered = false;
+
+                let src_mask_bin = u128::MAX - src_mask;
+                let src_map = Rc::get_mut(&mut self.src_map_v6).unwrap();
+                match src_map.get_mut(&src_mask_bin) {
+                    Some(map) => { map.insert((src_net, flow.src_port), true); },
+                    None => {
+                        let mut map = HashMap::new();
+                        map.insert((src_net, flow.src_port), true);
+                        src_map.insert(src_mask_bin, map);
+                        new_mask_registered = true;
+                    },
+                }
+
+                let dst_mask_bin = u128::MAX - dst_mask;
+                let dst_map = Rc::get_mut(&mut self.dst_map_v6).unwrap();
+                match dst_map.get_mut(&dst_mask_bin) {
+                    Some(map) => { map.insert((dst_net, flow.dst_port), true); },
+                    None => {
+                        let mut map = HashMap::new();
+                        map.insert((dst_net, flow.dst_port), true);
+                        dst_map.insert(dst_mask_bin, map);
+                        new_mask_registered = true;
+                    },
+                }
+
+                let flow_map = Rc::get_mut(&mut self.flow_map_v6).unwrap();
+                flow_map.insert((flow.proto, src_net, src_mask, flow.src_port, dst_net, dst_mask, flow.dst_port), flow.action);
+
+                if new_mask_registered {
+                    self.rebuild_mask_pairs_v6();
+                }
             },
         }
-        let flow_map = Rc::get_mut(&mut self.flow_map).unwrap(); 
-        flow_map.insert((flow.src_net, flow.src_mask, flow.src_port, flow.dst_net, flow.dst_mask, flow.dst_port), flow.action);
+    }
 
+    fn rebuild_mask_pairs_v4(&mut self) {
+        let src_masks = registered_masks_v4(&self.src_map_v4);
+        let dst_masks = registered_masks_v4(&self.dst_map_v4);
+        self.mask_pairs_v4 = Rc::new(combined_masks_by_specificity(&src_masks, &dst_masks, prefix_len_v4));
+    }
+
+    fn rebuild_mask_pairs_v6(&mut self) {
+        let src_masks = registered_masks_v6(&self.src_map_v6);
+        let dst_masks = registered_masks_v6(&self.dst_map_v6);
+        self.mask_pairs_v6 = Rc::new(combined_masks_by_specificity(&src_masks, &dst_masks, prefix_len_v6));
     }
 
     fn print(&mut self){
-        let flow_map = Rc::get_mut(&mut self.flow_map).unwrap(); 
-        for ((src_net, src_mask, src_port,dst_net, dst_mask, dst_port), action) in flow_map {
+        let flow_map_v4 = Rc::get_mut(&mut self.flow_map_v4).unwrap();
+        for ((proto, src_net, src_mask, src_port,dst_net, dst_mask, dst_port), action) in flow_map_v4 {
             let max_mask: u32 = 4294967295;
             let src_prefix_length: u32;
             if *src_mask == 0 {
@@ -103,80 +209,418 @@ impl FlowTable {
             let src = Ipv4Net::new(Ipv4Addr::new(octet[0], octet[1], octet[2], octet[3]), src_prefix_length as u8).unwrap();
             let octet = as_br(*dst_net);
             let dst = Ipv4Net::new(Ipv4Addr::new(octet[0], octet[1], octet[2], octet[3]), dst_prefix_length as u8).unwrap();
-            println!("src: {:?}:{:?} dst: {:?}:{:?} -> {:?}", src, src_port, dst, dst_port, action);
+            println!("proto: {:?} src: {:?}:{:?} dst: {:?}:{:?} -> {:?}", proto, src, src_port, dst, dst_port, action);
+        }
+
+        let flow_map_v6 = Rc::get_mut(&mut self.flow_map_v6).unwrap();
+        for ((proto, src_net, src_mask, src_port,dst_net, dst_mask, dst_port), action) in flow_map_v6 {
+            let max_mask: u128 = u128::MAX;
+            let src_prefix_length: u32;
+            if *src_mask == 0 {
+                src_prefix_length = 0;
+            } else {
+                src_prefix_length = 128 - ((max_mask - *src_mask + 1) as f64).log2() as u32;
+            }
+            let dst_prefix_length: u32;
+            if *dst_mask == 0 {
+                dst_prefix_length = 0;
+            } else {
+                dst_prefix_length = 128 - ((max_mask - *dst_mask + 1) as f64).log2() as u32;
+            }
+            let octet = as_br128(*src_net);
+            let src = Ipv6Net::new(Ipv6Addr::from(octet), src_prefix_length as u8).unwrap();
+            let octet = as_br128(*dst_net);
+            let dst = Ipv6Net::new(Ipv6Addr::from(octet), dst_prefix_length as u8).unwrap();
+            println!("proto: {:?} src: {:?}:{:?} dst: {:?}:{:?} -> {:?}", proto, src, src_port, dst, dst_port, action);
         }
     }
 
+    // A packet is resolved against the combined (src, dst) prefix pair, not src and dst
+    // independently: the src side's longest match and the dst side's longest match may
+    // belong to two different rules that were never registered together, so picking each
+    // side in isolation and then probing flow_map for that combination can miss an
+    // existing, less-specific rule that actually covers the packet. Instead every
+    // registered (src_mask, dst_mask) pair is tried together, most-specific pair first
+    // (by combined prefix length), so an explicit Deny at /32 correctly overrides a
+    // broader Allow at /0 covering the same packet, and vice versa. When two pairs tie on
+    // combined prefix length (e.g. src=/24,dst=/0 vs. src=/0,dst=/24), the longer src
+    // prefix wins the tie-break (see `combined_masks_by_specificity`) — an arbitrary but
+    // fixed and documented choice, so precedence never depends on registration order.
     fn match_flow(&mut self, packet: Packet) -> Option<Action>{
-        
-        // match specific src/dst port first
-        let src_net_specific = get_net_port(packet.src_ip, packet.src_port, self.src_map.clone());
-        let dst_net_specific = get_net_port(packet.dst_ip, packet.dst_port, self.dst_map.clone());
-        if src_net_specific.is_some() && dst_net_specific.is_some(){
-            let (src_net, src_mask,  src_port) = src_net_specific.unwrap();
-            let (dst_net, dst_mask, dst_port) = dst_net_specific.unwrap();
-            let res = self.flow_map.get(&(src_net, src_mask, src_port, dst_net, dst_mask, dst_port));
-            return res.cloned()
+        match packet.ip {
+            IpAddrPair::V4 { src_ip, dst_ip } => self.match_flow_v4(packet.proto, src_ip, packet.src_port, dst_ip, packet.dst_port),
+            IpAddrPair::V6 { src_ip, dst_ip } => self.match_flow_v6(packet.proto, src_ip, packet.src_port, dst_ip, packet.dst_port),
         }
+    }
+
+    fn match_flow_v4(&mut self, proto: Proto, src_ip: u32, src_port: u16, dst_ip: u32, dst_port: u16) -> Option<Action>{
+        // try the packet's own protocol first, then fall back to the Any wildcard
+        let res = self.match_flow_v4_proto(proto, src_ip, src_port, dst_ip, dst_port);
+        if res.is_some() || proto == Proto::Any {
+            return res
+        }
+        self.match_flow_v4_proto(Proto::Any, src_ip, src_port, dst_ip, dst_port)
+    }
 
-        // match specific src_port and 0 dst_port
-        let src_net_0 = get_net_port(packet.src_ip, 0, self.src_map.clone());
-        if src_net_0.is_some() && dst_net_specific.is_some(){
-            let (src_net, src_mask, src_port) = src_net_0.unwrap();
-            let (dst_net, dst_mask, dst_port) = dst_net_specific.unwrap();
-            let res = self.flow_map.get(&(src_net, src_mask, src_port, dst_net, dst_mask, dst_port));
-            return res.cloned()
+    fn match_flow_v4_proto(&mut self, proto: Proto, src_ip: u32, src_port: u16, dst_ip: u32, dst_port: u16) -> Option<Action>{
+        let mask_pairs = Rc::clone(&self.mask_pairs_v4);
+        for &(src_mask, dst_mask) in mask_pairs.iter() {
+            let src_net = src_ip & src_mask;
+            let dst_net = dst_ip & dst_mask;
+            // within a given (src_mask, dst_mask) pair, an exact port match still beats a wildcard one
+            for &(sp, dp) in &[(src_port, dst_port), (src_port, 0), (0, dst_port), (0, 0)] {
+                let res = self.flow_map_v4.get(&(proto, src_net, src_mask, sp, dst_net, dst_mask, dp));
+                if res.is_some() {
+                    return res.cloned()
+                }
+            }
         }
+        None
+    }
 
-        // match 0 src_port and specific dst_port
-        let dst_net_0 = get_net_port(packet.dst_ip, 0, self.dst_map.clone());
-        if src_net_specific.is_some() && dst_net_0.is_some(){
-            let (src_net,src_mask, src_port) = src_net_specific.unwrap();
-            let (dst_net,dst_mask, dst_port) = dst_net_0.unwrap();
-            let res = self.flow_map.get(&(src_net, src_mask, src_port, dst_net, dst_mask, dst_port));
-            return res.cloned()
+    fn match_flow_v6(&mut self, proto: Proto, src_ip: u128, src_port: u16, dst_ip: u128, dst_port: u16) -> Option<Action>{
+        // try the packet's own protocol first, then fall back to the Any wildcard
+        let res = self.match_flow_v6_proto(proto, src_ip, src_port, dst_ip, dst_port);
+        if res.is_some() || proto == Proto::Any {
+            return res
         }
+        self.match_flow_v6_proto(Proto::Any, src_ip, src_port, dst_ip, dst_port)
+    }
 
-        // match 0 src_port and 0 dst_port
-        if src_net_0.is_some() && dst_net_0.is_some(){
-            let (src_net,src_mask, src_port) = src_net_0.unwrap();
-            let (dst_net,dst_mask, dst_port) = dst_net_0.unwrap();
-            let res = self.flow_map.get(&(src_net, src_mask, src_port, dst_net, dst_mask, dst_port));
-            return res.cloned()
+    fn match_flow_v6_proto(&mut self, proto: Proto, src_ip: u128, src_port: u16, dst_ip: u128, dst_port: u16) -> Option<Action>{
+        let mask_pairs = Rc::clone(&self.mask_pairs_v6);
+        for &(src_mask, dst_mask) in mask_pairs.iter() {
+            let src_net = src_ip & src_mask;
+            let dst_net = dst_ip & dst_mask;
+            // within a given (src_mask, dst_mask) pair, an exact port match still beats a wildcard one
+            for &(sp, dp) in &[(src_port, dst_port), (src_port, 0), (0, dst_port), (0, 0)] {
+                let res = self.flow_map_v6.get(&(proto, src_net, src_mask, sp, dst_net, dst_mask, dp));
+                if res.is_some() {
+                    return res.cloned()
+                }
+            }
         }
         None
     }
 }
 
-fn get_net_port(ip: u32, port: u16, map: Rc<BTreeMap<u32, HashMap<(u32,u16), bool>>>) -> Option<(u32,u32,u16)>{
-    for (mask, map) in map.as_ref() {
-        let mask_bin = 4294967295 - mask;
-        let masked: u32 = ip & mask_bin;
-        let kv = map.get_key_value(&(masked, port));
-        match kv {
-            Some(((net, port),_)) => { return Some((net.clone(),mask_bin, port.clone())) },
-            None => { },
+// The BTreeMap key is the inverted (wildcard-bit) mask, so ascending iteration already
+// yields the narrowest prefixes first; convert each key back to the real netmask.
+fn registered_masks_v4(map: &BTreeMap<u32, HashMap<(u32,u16), bool>>) -> Vec<u32> {
+    map.keys().map(|wildcard| 4294967295 - wildcard).collect()
+}
+
+fn registered_masks_v6(map: &BTreeMap<u128, HashMap<(u128,u16), bool>>) -> Vec<u128> {
+    map.keys().map(|wildcard| u128::MAX - wildcard).collect()
+}
+
+fn prefix_len_v4(mask: u32) -> u32 {
+    let max_mask: u32 = 4294967295;
+    if mask == 0 {
+        0
+    } else {
+        32 - ((max_mask - mask + 1) as f32).log2() as u32
+    }
+}
+
+fn prefix_len_v6(mask: u128) -> u32 {
+    let max_mask: u128 = u128::MAX;
+    if mask == 0 {
+        0
+    } else {
+        128 - ((max_mask - mask + 1) as f64).log2() as u32
+    }
+}
+
+// Builds every (src_mask, dst_mask) pair from the two registered mask sets and orders
+// them most-specific-combination first, so the caller can probe flow_map pair by pair
+// and stop at the first hit instead of resolving each side independently. Pairs with an
+// equal combined prefix length (e.g. src=/24,dst=/0 vs. src=/0,dst=/24) are tie-broken by
+// the longer src prefix, so the ordering never depends on BTreeMap iteration order.
+fn combined_masks_by_specificity<M: Copy>(
+    src_masks: &[M],
+    dst_masks: &[M],
+    prefix_len: fn(M) -> u32,
+) -> Vec<(M, M)> {
+    let mut pairs: Vec<(M, M)> = Vec::with_capacity(src_masks.len() * dst_masks.len());
+    for &src_mask in src_masks {
+        for &dst_mask in dst_masks {
+            pairs.push((src_mask, dst_mask));
         }
     }
-    None
+    pairs.sort_by(|a, b| {
+        let specificity_a = prefix_len(a.0) + prefix_len(a.1);
+        let specificity_b = prefix_len(b.0) + prefix_len(b.1);
+        specificity_b.cmp(&specificity_a).then_with(|| prefix_len(b.0).cmp(&prefix_len(a.0)))
+    });
+    pairs
+}
+
+#[derive(Debug, Clone)]
+enum IpAddrPair {
+    V4 { src_ip: u32, dst_ip: u32 },
+    V6 { src_ip: u128, dst_ip: u128 },
+}
+
+// Mirrors smoltcp's TcpControl: the non-ACK control bit carried by a TCP segment.
+// ACK is tracked separately (`Packet::tcp_ack`) since it can be set alongside any of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpControl {
+    None,
+    Syn,
+    Fin,
+    Rst,
 }
 
 #[derive(Debug, Clone)]
 struct Packet {
-    src_ip: u32,
-    dst_ip: u32,
+    ip: IpAddrPair,
+    proto: Proto,
     src_port: u16,
     dst_port: u16,
+    tcp_control: TcpControl,
+    tcp_ack: bool,
 }
 
 impl Packet {
-    fn new(src_ip: Ipv4Addr, src_port: u16, dst_ip: Ipv4Addr, dst_port: u16) -> Self{
-        Self { 
-            src_ip: as_u32_be(&src_ip.octets()),
+    fn new_v4(src_ip: Ipv4Addr, src_port: u16, dst_ip: Ipv4Addr, dst_port: u16, proto: Proto) -> Self{
+        Self {
+            ip: IpAddrPair::V4 {
+                src_ip: as_u32_be(&src_ip.octets()),
+                dst_ip: as_u32_be(&dst_ip.octets()),
+            },
+            proto,
             src_port,
-            dst_ip: as_u32_be(&dst_ip.octets()),
             dst_port,
+            tcp_control: TcpControl::None,
+            tcp_ack: false,
+        }
+    }
+
+    fn new_v6(src_ip: Ipv6Addr, src_port: u16, dst_ip: Ipv6Addr, dst_port: u16, proto: Proto) -> Self{
+        Self {
+            ip: IpAddrPair::V6 {
+                src_ip: as_u128_be(&src_ip.octets()),
+                dst_ip: as_u128_be(&dst_ip.octets()),
+            },
+            proto,
+            src_port,
+            dst_port,
+            tcp_control: TcpControl::None,
+            tcp_ack: false,
+        }
+    }
+
+    // Attaches the TCP control flags observed on the wire so ConnTrack can drive its
+    // state machine; a no-op for non-TCP packets.
+    fn with_tcp_control(mut self, control: TcpControl, ack: bool) -> Self {
+        self.tcp_control = control;
+        self.tcp_ack = ack;
+        self
+    }
+
+    // Parses a received IPv4 frame (network byte order) into a Packet, reading the
+    // transport ports from the correct offset past a variable-length IHL/options header.
+    fn from_ipv4_bytes(buf: &[u8]) -> Result<Packet, ParseError> {
+        if buf.len() < 20 {
+            return Err(ParseError::Truncated);
+        }
+        let version = buf[0] >> 4;
+        if version != 4 {
+            return Err(ParseError::NotIpv4(version));
+        }
+        let header_len = ((buf[0] & 0x0f) as usize) * 4;
+        if header_len < 20 || buf.len() < header_len {
+            return Err(ParseError::Truncated);
+        }
+
+        let proto = match buf[9] {
+            1 => Proto::Icmp,
+            6 => Proto::Tcp,
+            17 => Proto::Udp,
+            other => return Err(ParseError::UnsupportedProtocol(other)),
+        };
+
+        let src_ip = Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]);
+        let dst_ip = Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]);
+
+        let (src_port, dst_port, tcp_control, tcp_ack) = match proto {
+            Proto::Tcp => {
+                // TCP header: ports at 0..4, flags byte at offset 13
+                if buf.len() < header_len + 14 {
+                    return Err(ParseError::Truncated);
+                }
+                let src_port = u16::from_be_bytes([buf[header_len], buf[header_len + 1]]);
+                let dst_port = u16::from_be_bytes([buf[header_len + 2], buf[header_len + 3]]);
+                let flags = buf[header_len + 13];
+                let ack = flags & 0x10 != 0;
+                let control = if flags & 0x04 != 0 {
+                    TcpControl::Rst
+                } else if flags & 0x02 != 0 {
+                    TcpControl::Syn
+                } else if flags & 0x01 != 0 {
+                    TcpControl::Fin
+                } else {
+                    TcpControl::None
+                };
+                (src_port, dst_port, control, ack)
+            },
+            Proto::Udp => {
+                if buf.len() < header_len + 4 {
+                    return Err(ParseError::Truncated);
+                }
+                let src_port = u16::from_be_bytes([buf[header_len], buf[header_len + 1]]);
+                let dst_port = u16::from_be_bytes([buf[header_len + 2], buf[header_len + 3]]);
+                (src_port, dst_port, TcpControl::None, false)
+            },
+            Proto::Icmp | Proto::Any => (0, 0, TcpControl::None, false),
+        };
+
+        Ok(Packet::new_v4(src_ip, src_port, dst_ip, dst_port, proto).with_tcp_control(tcp_control, tcp_ack))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseError {
+    Truncated,
+    NotIpv4(u8),
+    UnsupportedProtocol(u8),
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Truncated => write!(f, "packet buffer too short for its declared header length"),
+            ParseError::NotIpv4(version) => write!(f, "unsupported IP version {}, expected 4", version),
+            ParseError::UnsupportedProtocol(proto) => write!(f, "unsupported L4 protocol {}", proto),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// A small TCP state machine, advanced from the control flags observed on each packet
+// of a tracked connection. Non-TCP flows (UDP/ICMP) are treated as Established as soon
+// as a single packet has been allowed, since there is no handshake to track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    SynSent,
+    Established,
+    FinWait,
+    Closed,
+}
+
+fn next_conn_state(current: Option<ConnState>, control: TcpControl, ack: bool) -> ConnState {
+    match (current, control) {
+        (_, TcpControl::Rst) => ConnState::Closed,
+        (_, TcpControl::Fin) => ConnState::FinWait,
+        (None, TcpControl::Syn) => ConnState::SynSent,
+        (Some(ConnState::SynSent), _) if ack => ConnState::Established,
+        (Some(state), _) => state,
+        (None, _) => ConnState::Established,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ConnEntry {
+    state: ConnState,
+    action: Action,
+    last_seen: Instant,
+}
+
+// Sits in front of FlowTable to turn the stateless ACL into a stateful firewall: once a
+// flow has been allowed, its reverse direction is let through on the strength of the
+// tracked connection instead of a fresh FlowTable lookup. Keyed on the same dual-stack
+// split as FlowTable (separate v4/v6 maps) rather than a widened address type, so a v4
+// and a v6 flow can never collide in the same map.
+#[derive(Debug, Clone)]
+struct ConnTrack {
+    conns_v4: HashMap<(u32, u32, u16, u16, Proto), ConnEntry>,
+    conns_v6: HashMap<(u128, u128, u16, u16, Proto), ConnEntry>,
+}
+
+impl ConnTrack {
+    fn new() -> Self {
+        Self {
+            conns_v4: HashMap::new(),
+            conns_v6: HashMap::new(),
+        }
+    }
+
+    // Consults the tracked connection for this packet first; only on a miss does it fall
+    // through to `flow_table`. An `Allow` from the fall-through installs both the forward
+    // and reverse tuples so the return traffic hits the fast path next time.
+    fn check(&mut self, flow_table: &mut FlowTable, packet: Packet) -> Option<Action> {
+        match packet.ip {
+            IpAddrPair::V4 { src_ip, dst_ip } => self.check_v4(flow_table, packet, src_ip, dst_ip),
+            IpAddrPair::V6 { src_ip, dst_ip } => self.check_v6(flow_table, packet, src_ip, dst_ip),
+        }
+    }
+
+    fn check_v4(&mut self, flow_table: &mut FlowTable, packet: Packet, src_ip: u32, dst_ip: u32) -> Option<Action> {
+        let fwd_key = (src_ip, dst_ip, packet.src_port, packet.dst_port, packet.proto);
+        let rev_key = (dst_ip, src_ip, packet.dst_port, packet.src_port, packet.proto);
+
+        // fwd_key and rev_key are always installed (and updated) as a pair below, so a
+        // single lookup on the key matching this packet's direction covers both legs of
+        // an established connection; a Closed entry is stale and must not short-circuit
+        // a fresh connection reusing the same 5-tuple, so it falls through to match_flow.
+        if let Some(entry) = self.conns_v4.get_mut(&fwd_key) {
+            if entry.state != ConnState::Closed {
+                let action = entry.action.clone();
+                let state = next_conn_state(Some(entry.state), packet.tcp_control, packet.tcp_ack);
+                let now = Instant::now();
+                entry.state = state;
+                entry.last_seen = now;
+                if let Some(rev) = self.conns_v4.get_mut(&rev_key) { rev.state = state; rev.last_seen = now; }
+                return Some(action);
+            }
+            self.conns_v4.remove(&fwd_key);
+            self.conns_v4.remove(&rev_key);
         }
+
+        let res = flow_table.match_flow(packet.clone());
+        if let Some(action @ Action::Allow(_)) = &res {
+            let state = next_conn_state(None, packet.tcp_control, packet.tcp_ack);
+            let now = Instant::now();
+            self.conns_v4.insert(fwd_key, ConnEntry { state, action: action.clone(), last_seen: now });
+            self.conns_v4.insert(rev_key, ConnEntry { state, action: action.clone(), last_seen: now });
+        }
+        res
+    }
+
+    fn check_v6(&mut self, flow_table: &mut FlowTable, packet: Packet, src_ip: u128, dst_ip: u128) -> Option<Action> {
+        let fwd_key = (src_ip, dst_ip, packet.src_port, packet.dst_port, packet.proto);
+        let rev_key = (dst_ip, src_ip, packet.dst_port, packet.src_port, packet.proto);
+
+        if let Some(entry) = self.conns_v6.get_mut(&fwd_key) {
+            if entry.state != ConnState::Closed {
+                let action = entry.action.clone();
+                let state = next_conn_state(Some(entry.state), packet.tcp_control, packet.tcp_ack);
+                let now = Instant::now();
+                entry.state = state;
+                entry.last_seen = now;
+                if let Some(rev) = self.conns_v6.get_mut(&rev_key) { rev.state = state; rev.last_seen = now; }
+                return Some(action);
+            }
+            self.conns_v6.remove(&fwd_key);
+            self.conns_v6.remove(&rev_key);
+        }
+
+        let res = flow_table.match_flow(packet.clone());
+        if let Some(action @ Action::Allow(_)) = &res {
+            let state = next_conn_state(None, packet.tcp_control, packet.tcp_ack);
+            let now = Instant::now();
+            self.conns_v6.insert(fwd_key, ConnEntry { state, action: action.clone(), last_seen: now });
+            self.conns_v6.insert(rev_key, ConnEntry { state, action: action.clone(), last_seen: now });
+        }
+        res
+    }
+
+    // Drops connections that are Closed (RST seen) or have been idle past `ttl`.
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        self.conns_v4.retain(|_, entry| entry.state != ConnState::Closed && now.duration_since(entry.last_seen) < ttl);
+        self.conns_v6.retain(|_, entry| entry.state != ConnState::Closed && now.duration_since(entry.last_seen) < ttl);
     }
 }
 
@@ -184,41 +628,54 @@ fn main() {
 
     let mut flow_table = FlowTable::new();
 
-    flow_table.add_flow(Flow::new("1.0.0.0/25".parse().unwrap(),
+    flow_table.add_flow(Flow::new_v4("1.0.0.0/25".parse().unwrap(),
         0,
         "2.0.0.0/25".parse().unwrap(),
         0,
+        Proto::Any,
         Action::Allow("int1".into())
     ));
 
-    
 
-    flow_table.add_flow(Flow::new("3.0.0.0/24".parse().unwrap(),
+
+    flow_table.add_flow(Flow::new_v4("3.0.0.0/24".parse().unwrap(),
         0,
         "4.0.0.0/24".parse().unwrap(),
         0,
+        Proto::Any,
         Action::Allow("int2".into())
     ));
 
-    flow_table.add_flow(Flow::new("5.0.0.0/23".parse().unwrap(),
+    flow_table.add_flow(Flow::new_v4("5.0.0.0/23".parse().unwrap(),
         0,
         "6.0.0.0/23".parse().unwrap(),
         0,
+        Proto::Any,
         Action::Allow("int3".into())
     ));
 
-    flow_table.add_flow(Flow::new("0.0.0.0/0".parse().unwrap(),
+    flow_table.add_flow(Flow::new_v4("0.0.0.0/0".parse().unwrap(),
         0,
         "0.0.0.0/0".parse().unwrap(),
         0,
+        Proto::Any,
         Action::Allow("int4".into())
     ));
+
+    flow_table.add_flow(Flow::new_v6("2001:db8:1::/64".parse().unwrap(),
+        0,
+        "2001:db8:2::/64".parse().unwrap(),
+        0,
+        Proto::Any,
+        Action::Allow("int5".into())
+    ));
+
     println!("flow table:");
     flow_table.print();
 
     println!("1st stage lookups:");
-    
-    let packet = Packet::new("1.0.0.1".parse().unwrap(), 0, "2.0.0.1".parse().unwrap(), 0);
+
+    let packet = Packet::new_v4("1.0.0.1".parse().unwrap(), 0, "2.0.0.1".parse().unwrap(), 0, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -228,7 +685,7 @@ fn main() {
     }
     println!("-- specific sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("1.0.0.1".parse().unwrap(), 80, "2.0.0.1".parse().unwrap(), 0);
+    let packet = Packet::new_v4("1.0.0.1".parse().unwrap(), 80, "2.0.0.1".parse().unwrap(), 0, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -238,7 +695,7 @@ fn main() {
     }
     println!("-- wildcard sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("1.0.0.1".parse().unwrap(), 0, "2.0.0.1".parse().unwrap(), 80);
+    let packet = Packet::new_v4("1.0.0.1".parse().unwrap(), 0, "2.0.0.1".parse().unwrap(), 80, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -248,7 +705,7 @@ fn main() {
     }
     println!("-- specific sport - wildcard dport {:?}", now.elapsed());
 
-    let packet = Packet::new("1.0.0.1".parse().unwrap(), 80, "2.0.0.1".parse().unwrap(), 80);
+    let packet = Packet::new_v4("1.0.0.1".parse().unwrap(), 80, "2.0.0.1".parse().unwrap(), 80, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -259,9 +716,9 @@ fn main() {
     println!("-- specific sport - wildcard dport {:?}", now.elapsed());
 
     println!("2nd stage lookups:");
-    
-    let packet = Packet::new("3.0.0.1".parse().unwrap(), 0, "4.0.0.1".parse().unwrap(), 0);
-    
+
+    let packet = Packet::new_v4("3.0.0.1".parse().unwrap(), 0, "4.0.0.1".parse().unwrap(), 0, Proto::Tcp);
+
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -270,8 +727,8 @@ fn main() {
     }
     println!("-- specific sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("3.0.0.1".parse().unwrap(), 80, "4.0.0.1".parse().unwrap(), 0);
-    
+    let packet = Packet::new_v4("3.0.0.1".parse().unwrap(), 80, "4.0.0.1".parse().unwrap(), 0, Proto::Tcp);
+
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -280,8 +737,8 @@ fn main() {
     }
     println!("-- wildcard sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("3.0.0.1".parse().unwrap(), 0, "4.0.0.1".parse().unwrap(), 80);
-    
+    let packet = Packet::new_v4("3.0.0.1".parse().unwrap(), 0, "4.0.0.1".parse().unwrap(), 80, Proto::Tcp);
+
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -290,8 +747,8 @@ fn main() {
     }
     println!("-- specific sport - wildcard dport {:?}", now.elapsed());
 
-    let packet = Packet::new("3.0.0.1".parse().unwrap(), 80, "4.0.0.1".parse().unwrap(), 80);
-    
+    let packet = Packet::new_v4("3.0.0.1".parse().unwrap(), 80, "4.0.0.1".parse().unwrap(), 80, Proto::Tcp);
+
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -301,8 +758,8 @@ fn main() {
     println!("-- wildcard sport - wildcard dport {:?}", now.elapsed());
 
     println!("3rd stage lookups:");
-    
-    let packet = Packet::new("5.0.0.1".parse().unwrap(), 0, "6.0.0.1".parse().unwrap(), 0);
+
+    let packet = Packet::new_v4("5.0.0.1".parse().unwrap(), 0, "6.0.0.1".parse().unwrap(), 0, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -312,7 +769,7 @@ fn main() {
     }
     println!("-- specific sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("5.0.0.1".parse().unwrap(), 80, "6.0.0.1".parse().unwrap(), 0);
+    let packet = Packet::new_v4("5.0.0.1".parse().unwrap(), 80, "6.0.0.1".parse().unwrap(), 0, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -322,7 +779,7 @@ fn main() {
     }
     println!("-- wildcard sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("5.0.0.1".parse().unwrap(), 0, "6.0.0.1".parse().unwrap(), 80);
+    let packet = Packet::new_v4("5.0.0.1".parse().unwrap(), 0, "6.0.0.1".parse().unwrap(), 80, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -332,7 +789,7 @@ fn main() {
     }
     println!("-- specific sport - wildcard dport {:?}", now.elapsed());
 
-    let packet = Packet::new("5.0.0.1".parse().unwrap(), 80, "6.0.0.1".parse().unwrap(), 80);
+    let packet = Packet::new_v4("5.0.0.1".parse().unwrap(), 80, "6.0.0.1".parse().unwrap(), 80, Proto::Tcp);
 
     let now = Instant::now();
     for _ in 0..1000000{
@@ -343,8 +800,8 @@ fn main() {
     println!("-- wildcard sport - wildcard dport {:?}", now.elapsed());
 
     println!("4th stage lookups:");
-     
-    let packet = Packet::new("1.2.3.5".parse().unwrap(), 0, "5.6.7.8".parse().unwrap(), 0);
+
+    let packet = Packet::new_v4("1.2.3.5".parse().unwrap(), 0, "5.6.7.8".parse().unwrap(), 0, Proto::Tcp);
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -353,7 +810,7 @@ fn main() {
     }
     println!("-- specific sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("1.2.3.5".parse().unwrap(), 80, "5.6.7.8".parse().unwrap(), 0);
+    let packet = Packet::new_v4("1.2.3.5".parse().unwrap(), 80, "5.6.7.8".parse().unwrap(), 0, Proto::Tcp);
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -362,7 +819,7 @@ fn main() {
     }
     println!("-- wildcard sport - specific dport {:?}", now.elapsed());
 
-    let packet = Packet::new("1.2.3.5".parse().unwrap(), 0, "5.6.7.8".parse().unwrap(), 80);
+    let packet = Packet::new_v4("1.2.3.5".parse().unwrap(), 0, "5.6.7.8".parse().unwrap(), 80, Proto::Tcp);
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -371,7 +828,7 @@ fn main() {
     }
     println!("-- specific sport - wildcard dport {:?}", now.elapsed());
 
-    let packet = Packet::new("1.2.3.5".parse().unwrap(), 80, "5.6.7.8".parse().unwrap(), 80);
+    let packet = Packet::new_v4("1.2.3.5".parse().unwrap(), 80, "5.6.7.8".parse().unwrap(), 80, Proto::Tcp);
     let now = Instant::now();
     for _ in 0..1000000{
         let res = flow_table.match_flow(packet.clone());
@@ -380,7 +837,92 @@ fn main() {
     }
     println!("-- wildcard sport - wildcard dport {:?}", now.elapsed());
 
+    println!("5th stage lookups (ipv6):");
+
+    let packet = Packet::new_v6("2001:db8:1::1".parse().unwrap(), 0, "2001:db8:2::1".parse().unwrap(), 0, Proto::Tcp);
+    let now = Instant::now();
+    for _ in 0..1000000{
+        let res = flow_table.match_flow(packet.clone());
+        let res = res;
+        assert_eq!(Some(Action::Allow("int5".into())),res);
+    }
+    println!("-- specific sport - specific dport {:?}", now.elapsed());
+
+    println!("6th stage lookups (proto-specific):");
+
+    flow_table.add_flow(Flow::new_v4("7.0.0.0/24".parse().unwrap(),
+        0,
+        "8.0.0.0/24".parse().unwrap(),
+        443,
+        Proto::Tcp,
+        Action::Allow("int6-tcp".into())
+    ));
+
+    flow_table.add_flow(Flow::new_v4("7.0.0.0/24".parse().unwrap(),
+        0,
+        "8.0.0.0/24".parse().unwrap(),
+        443,
+        Proto::Udp,
+        Action::Deny
+    ));
+
+    let packet = Packet::new_v4("7.0.0.1".parse().unwrap(), 0, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp);
+    assert_eq!(Some(Action::Allow("int6-tcp".into())), flow_table.match_flow(packet));
+    println!("-- tcp/443 -> Allow(int6-tcp)");
+
+    let packet = Packet::new_v4("7.0.0.1".parse().unwrap(), 0, "8.0.0.1".parse().unwrap(), 443, Proto::Udp);
+    assert_eq!(Some(Action::Deny), flow_table.match_flow(packet));
+    println!("-- udp/443 -> Deny");
+
+    println!("7th stage: parsing a raw IPv4/TCP frame");
+
+    // version/IHL=5, total len 40, src 7.0.0.1, dst 8.0.0.1, proto TCP, sport 12345, dport
+    // 443, data offset=5 words, flags=SYN
+    let raw: [u8; 40] = [
+        0x45, 0x00, 0x00, 0x28,
+        0x00, 0x00, 0x00, 0x00,
+        0x40, 0x06, 0x00, 0x00,
+        7, 0, 0, 1,
+        8, 0, 0, 1,
+        0x30, 0x39, 0x01, 0xbb,
+        0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0x50, 0x02, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+    ];
+    let packet = Packet::from_ipv4_bytes(&raw).expect("valid IPv4/TCP frame");
+    assert_eq!(Some(Action::Allow("int6-tcp".into())), flow_table.match_flow(packet));
+    println!("-- parsed tcp/443 -> Allow(int6-tcp)");
+
+    let err = Packet::from_ipv4_bytes(&raw[..10]).unwrap_err();
+    println!("-- truncated frame -> {}", err);
+
+    println!("8th stage: stateful conntrack");
+
+    let mut conn_track = ConnTrack::new();
+
+    let syn = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+        .with_tcp_control(TcpControl::Syn, false);
+    assert_eq!(Some(Action::Allow("int6-tcp".into())), conn_track.check(&mut flow_table, syn));
+    println!("-- forward SYN -> Allow(int6-tcp), tracked");
+
+    let syn_ack = Packet::new_v4("8.0.0.1".parse().unwrap(), 443, "7.0.0.1".parse().unwrap(), 55000, Proto::Tcp)
+        .with_tcp_control(TcpControl::Syn, true);
+    assert_eq!(Some(Action::Allow("int6-tcp".into())), conn_track.check(&mut flow_table, syn_ack));
+    println!("-- return SYN-ACK -> Allow via conntrack, no FlowTable lookup needed");
+
+    let ack = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+        .with_tcp_control(TcpControl::None, true);
+    assert_eq!(Some(Action::Allow("int6-tcp".into())), conn_track.check(&mut flow_table, ack));
+    println!("-- forward ACK -> Established");
 
+    let fin = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+        .with_tcp_control(TcpControl::Fin, true);
+    assert_eq!(Some(Action::Allow("int6-tcp".into())), conn_track.check(&mut flow_table, fin));
+    println!("-- forward FIN -> FinWait, still allowed on the existing entry");
+
+    conn_track.expire(Instant::now(), Duration::from_secs(0));
+    println!("-- expire(ttl=0) evicts all idle entries: {} v4 conns left", conn_track.conns_v4.len());
 }
 
 fn as_br(x: u32) -> [u8; 4]{
@@ -394,3 +936,188 @@ fn as_u32_be(array: &[u8;4]) -> u32 {
     ((array[3] as u32) << 0)
 }
 
+fn as_br128(x: u128) -> [u8; 16]{
+    x.to_be_bytes()
+}
+
+fn as_u128_be(array: &[u8;16]) -> u128 {
+    u128::from_be_bytes(*array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Before the combined lookup, the src side's longest match (a /24) and the dst
+    // side's longest match (a /32) belonged to two different rules that were never
+    // registered together, so the probe into flow_map missed and returned None even
+    // though a valid, less-specific /24-vs-/24 rule covered the packet.
+    #[test]
+    fn overlapping_src_and_dst_prefixes_resolve_to_the_covering_rule() {
+        let mut flow_table = FlowTable::new();
+
+        flow_table.add_flow(Flow::new_v4("192.168.1.0/24".parse().unwrap(),
+            0,
+            "10.0.0.0/24".parse().unwrap(),
+            0,
+            Proto::Any,
+            Action::Allow("covering".into())
+        ));
+
+        flow_table.add_flow(Flow::new_v4("0.0.0.0/0".parse().unwrap(),
+            0,
+            "10.0.0.5/32".parse().unwrap(),
+            0,
+            Proto::Any,
+            Action::Allow("other-dst".into())
+        ));
+
+        let packet = Packet::new_v4("192.168.1.5".parse().unwrap(), 0, "10.0.0.5".parse().unwrap(), 0, Proto::Any);
+        assert_eq!(Some(Action::Allow("covering".into())), flow_table.match_flow(packet));
+    }
+
+    // A more specific /25 Allow overlapping a broader /24 Deny on the same destination
+    // must win, regardless of which rule was registered first.
+    #[test]
+    fn most_specific_prefix_wins_over_a_broader_overlapping_rule() {
+        let mut flow_table = FlowTable::new();
+
+        flow_table.add_flow(Flow::new_v4("10.0.0.0/24".parse().unwrap(),
+            0,
+            "0.0.0.0/0".parse().unwrap(),
+            0,
+            Proto::Any,
+            Action::Deny
+        ));
+
+        flow_table.add_flow(Flow::new_v4("10.0.0.0/25".parse().unwrap(),
+            0,
+            "0.0.0.0/0".parse().unwrap(),
+            0,
+            Proto::Any,
+            Action::Allow("permitted".into())
+        ));
+
+        let packet = Packet::new_v4("10.0.0.10".parse().unwrap(), 0, "1.2.3.4".parse().unwrap(), 0, Proto::Any);
+        assert_eq!(Some(Action::Allow("permitted".into())), flow_table.match_flow(packet));
+
+        let packet = Packet::new_v4("10.0.0.200".parse().unwrap(), 0, "1.2.3.4".parse().unwrap(), 0, Proto::Any);
+        assert_eq!(Some(Action::Deny), flow_table.match_flow(packet));
+    }
+
+    // Once the forward SYN has been allowed by FlowTable, the return-direction packet
+    // must be let through by ConnTrack alone, even for a reverse 5-tuple that has no
+    // matching rule of its own in FlowTable.
+    #[test]
+    fn conn_track_allows_return_direction_without_a_flow_table_rule() {
+        let mut flow_table = FlowTable::new();
+        flow_table.add_flow(Flow::new_v4("7.0.0.0/24".parse().unwrap(),
+            0,
+            "8.0.0.0/24".parse().unwrap(),
+            443,
+            Proto::Tcp,
+            Action::Allow("web".into())
+        ));
+        let mut conn_track = ConnTrack::new();
+
+        let syn = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+            .with_tcp_control(TcpControl::Syn, false);
+        assert_eq!(Some(Action::Allow("web".into())), conn_track.check(&mut flow_table, syn));
+
+        // No rule exists for 8.0.0.1:443 -> 7.0.0.1:55000, so a fresh FlowTable lookup
+        // would miss; ConnTrack must still allow it as the established return direction.
+        let reply = Packet::new_v4("8.0.0.1".parse().unwrap(), 443, "7.0.0.1".parse().unwrap(), 55000, Proto::Tcp)
+            .with_tcp_control(TcpControl::None, true);
+        assert_eq!(Some(Action::Allow("web".into())), conn_track.check(&mut flow_table, reply));
+    }
+
+    // A RST closes the tracked connection, and `expire` with a zero TTL must then drop it.
+    #[test]
+    fn conn_track_expires_closed_connections() {
+        let mut flow_table = FlowTable::new();
+        flow_table.add_flow(Flow::new_v4("7.0.0.0/24".parse().unwrap(),
+            0,
+            "8.0.0.0/24".parse().unwrap(),
+            443,
+            Proto::Tcp,
+            Action::Allow("web".into())
+        ));
+        let mut conn_track = ConnTrack::new();
+
+        let syn = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+            .with_tcp_control(TcpControl::Syn, false);
+        conn_track.check(&mut flow_table, syn);
+
+        let rst = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+            .with_tcp_control(TcpControl::Rst, true);
+        conn_track.check(&mut flow_table, rst);
+
+        conn_track.expire(Instant::now(), Duration::from_secs(60));
+        assert!(conn_track.conns_v4.is_empty());
+    }
+
+    // A Closed entry (RST seen) must not keep serving its cached action to a new
+    // connection that reuses the same 5-tuple before expire() has had a chance to run;
+    // the new SYN has to be re-evaluated against FlowTable instead of trusting the stale
+    // verdict from the previous, already-closed connection.
+    #[test]
+    fn conn_track_does_not_reuse_a_closed_connections_verdict() {
+        let mut flow_table = FlowTable::new();
+        flow_table.add_flow(Flow::new_v4("7.0.0.0/24".parse().unwrap(),
+            0,
+            "8.0.0.0/24".parse().unwrap(),
+            443,
+            Proto::Tcp,
+            Action::Allow("web".into())
+        ));
+        let mut conn_track = ConnTrack::new();
+
+        let syn = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+            .with_tcp_control(TcpControl::Syn, false);
+        conn_track.check(&mut flow_table, syn);
+
+        let rst = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+            .with_tcp_control(TcpControl::Rst, true);
+        conn_track.check(&mut flow_table, rst);
+
+        // the rule is tightened after the RST, before the same 5-tuple is reused
+        flow_table.add_flow(Flow::new_v4("7.0.0.1/32".parse().unwrap(),
+            55000,
+            "8.0.0.1/32".parse().unwrap(),
+            443,
+            Proto::Tcp,
+            Action::Deny
+        ));
+
+        let new_syn = Packet::new_v4("7.0.0.1".parse().unwrap(), 55000, "8.0.0.1".parse().unwrap(), 443, Proto::Tcp)
+            .with_tcp_control(TcpControl::Syn, false);
+        assert_eq!(Some(Action::Deny), conn_track.check(&mut flow_table, new_syn));
+    }
+
+    // src=/24,dst=/0 and src=/0,dst=/24 have equal combined prefix length (24), so which
+    // one wins must come from the documented tie-break (longer src prefix), not from
+    // registration order.
+    #[test]
+    fn equal_specificity_ties_are_broken_by_the_longer_src_prefix() {
+        let mut flow_table = FlowTable::new();
+
+        flow_table.add_flow(Flow::new_v4("0.0.0.0/0".parse().unwrap(),
+            0,
+            "10.0.0.0/24".parse().unwrap(),
+            0,
+            Proto::Any,
+            Action::Deny
+        ));
+
+        flow_table.add_flow(Flow::new_v4("192.168.1.0/24".parse().unwrap(),
+            0,
+            "0.0.0.0/0".parse().unwrap(),
+            0,
+            Proto::Any,
+            Action::Allow("longer-src-wins".into())
+        ));
+
+        let packet = Packet::new_v4("192.168.1.5".parse().unwrap(), 0, "10.0.0.5".parse().unwrap(), 0, Proto::Any);
+        assert_eq!(Some(Action::Allow("longer-src-wins".into())), flow_table.match_flow(packet));
+    }
+}